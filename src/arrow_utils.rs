@@ -1,5 +1,5 @@
 use arrow::{
-    array::ArrayRef,
+    array::{Array, ArrayRef, BooleanArray, Float64Array, Int32Array, Int64Array, StringArray},
     util::display::{ArrayFormatter, FormatOptions},
 };
 
@@ -10,3 +10,28 @@ pub fn value_string(column: &ArrayRef, row: usize) -> String {
         Err(e) => e.to_string(),
     }
 }
+
+/// Like `value_string()` but preserves numeric, boolean and null types
+/// instead of stringifying everything, so JSON consumers get real numbers.
+/// Falls back to `value_string()` for array types we don't special-case.
+pub fn value_json(column: &ArrayRef, row: usize) -> serde_json::Value {
+    if column.is_null(row) {
+        return serde_json::Value::Null;
+    }
+    if let Some(arr) = column.as_any().downcast_ref::<Int64Array>() {
+        return serde_json::Value::from(arr.value(row));
+    }
+    if let Some(arr) = column.as_any().downcast_ref::<Int32Array>() {
+        return serde_json::Value::from(arr.value(row));
+    }
+    if let Some(arr) = column.as_any().downcast_ref::<Float64Array>() {
+        return serde_json::Value::from(arr.value(row));
+    }
+    if let Some(arr) = column.as_any().downcast_ref::<BooleanArray>() {
+        return serde_json::Value::from(arr.value(row));
+    }
+    if let Some(arr) = column.as_any().downcast_ref::<StringArray>() {
+        return serde_json::Value::from(arr.value(row).to_string());
+    }
+    serde_json::Value::from(value_string(column, row))
+}
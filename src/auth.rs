@@ -0,0 +1,183 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use axum::{
+    extract::{Form, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar,
+};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+use crate::{AppError, AppState};
+
+/// Name of the cookie carrying the signed session token.
+pub const SESSION_COOKIE: &str = "session";
+
+/// How long a session token stays valid for.
+const SESSION_LIFETIME_SECS: u64 = 60 * 60 * 24;
+
+/// Configuration for the authentication layer: the secret used to sign
+/// session tokens, the shared password required to log in, and an optional
+/// allowlist of usernames.
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    pub secret: String,
+    pub password: String,
+    pub allowed_users: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    exp: u64,
+}
+
+impl AuthConfig {
+    fn user_allowed(&self, user: &str) -> bool {
+        match &self.allowed_users {
+            Some(allowed) => allowed.iter().any(|u| u == user),
+            None => true,
+        }
+    }
+
+    fn sign(&self, user: &str) -> Result<String> {
+        let exp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + SESSION_LIFETIME_SECS;
+        let claims = Claims {
+            sub: user.to_string(),
+            exp,
+        };
+        Ok(encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(self.secret.as_bytes()),
+        )?)
+    }
+
+    fn verify(&self, token: &str) -> Result<Claims> {
+        let data = decode::<Claims>(
+            token,
+            &DecodingKey::from_secret(self.secret.as_bytes()),
+            &Validation::default(),
+        )?;
+        Ok(data.claims)
+    }
+}
+
+/// Compare two strings without short-circuiting on the first differing
+/// byte, so a login attempt can't be timed to recover the password one
+/// character at a time.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginBody {
+    pub username: String,
+    pub password: String,
+}
+
+/// `GET /login` — plain HTML form for the human-facing `/` UI, since the
+/// pages behind `require_auth_redirect` send browsers here instead of
+/// returning a bare 401.
+pub async fn login_form() -> Html<&'static str> {
+    Html(
+        r#"
+<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="UTF-8">
+<title>SLURM Query — Log in</title>
+</head>
+<body>
+
+    <form action="/login" method="POST" style="width: 300px; margin: 10% auto; text-align: center">
+        <h1>Log in</h1>
+        <p><input name="username" type="text" placeholder="Username" autocomplete="username" required style="width: 100%"></p>
+        <p><input name="password" type="password" placeholder="Password" autocomplete="current-password" required style="width: 100%"></p>
+        <p><input type="submit" value="Log in"></p>
+    </form>
+
+</body>
+</html>
+"#,
+    )
+}
+
+/// `POST /login` with a `username=...&password=...` form body — issues a
+/// signed session cookie if `password` matches the configured shared
+/// password and `username` is on the allowed-user list (or there is no
+/// allowlist), then redirects to `/`. Returns 401 (not 500) if either
+/// check fails, since that's a rejected login, not a server error.
+pub async fn login(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    Form(body): Form<LoginBody>,
+) -> Response {
+    if !constant_time_eq(&body.password, &state.auth.password)
+        || !state.auth.user_allowed(&body.username)
+    {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let token = match state.auth.sign(&body.username) {
+        Ok(token) => token,
+        Err(e) => return AppError::from(e).into_response(),
+    };
+    let cookie = Cookie::build((SESSION_COOKIE, token))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .build();
+
+    (jar.add(cookie), Redirect::to("/")).into_response()
+}
+
+/// Middleware that rejects requests with 401 unless they carry a valid
+/// session cookie. Used for the JSON/SSE API routes, where a redirect to
+/// an HTML login page wouldn't make sense for callers.
+pub async fn require_auth(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(cookie) = jar.get(SESSION_COOKIE) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    match state.auth.verify(cookie.value()) {
+        Ok(_claims) => next.run(request).await,
+        Err(_) => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+/// Like `require_auth`, but redirects to `/login` instead of returning a
+/// bare 401. Used for the human-facing HTML page, so a browser with no
+/// (or an expired) session cookie ends up somewhere it can actually log
+/// in rather than a dead end.
+pub async fn require_auth_redirect(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authed = jar
+        .get(SESSION_COOKIE)
+        .is_some_and(|cookie| state.auth.verify(cookie.value()).is_ok());
+
+    if authed {
+        next.run(request).await
+    } else {
+        Redirect::to("/login").into_response()
+    }
+}
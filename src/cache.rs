@@ -0,0 +1,48 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tempfile::TempDir;
+use tokio::sync::Mutex;
+
+use crate::{config::Config, squeue_json};
+
+/// Caches the most recent `squeue --json` snapshot for `config.snapshot_ttl_secs`,
+/// so concurrent requests don't each shell out to `squeue` and reimport into
+/// DuckDB.
+///
+/// Refreshes are single-flighted: the lock guarding the cached entry is
+/// held for the duration of the refresh, so if several callers arrive on a
+/// stale/empty cache at once, only the first actually runs `squeue` and the
+/// rest simply wait for it and then read the same result.
+pub struct SnapshotCache {
+    ttl: Duration,
+    config: Arc<Config>,
+    entry: Mutex<Option<(Arc<TempDir>, Instant)>>,
+}
+
+impl SnapshotCache {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.snapshot_ttl_secs),
+            config,
+            entry: Mutex::new(None),
+        }
+    }
+
+    /// Return the current snapshot, refreshing it first if it's missing or
+    /// older than `ttl`.
+    pub async fn get(&self) -> Result<Arc<TempDir>> {
+        let mut entry = self.entry.lock().await;
+
+        if let Some((dir, captured_at)) = entry.as_ref() {
+            if captured_at.elapsed() < self.ttl {
+                return Ok(Arc::clone(dir));
+            }
+        }
+
+        let dir = Arc::new(squeue_json(&self.config).await?);
+        *entry = Some((Arc::clone(&dir), Instant::now()));
+        Ok(dir)
+    }
+}
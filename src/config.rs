@@ -0,0 +1,66 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+/// Service configuration, loaded from a TOML file by `main()`.
+/// Anything not present in the file falls back to its default.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Address the HTTP server listens on.
+    pub listen_addr: String,
+
+    /// The scheduler command to run, e.g. `squeue`, `sacct`, or a wrapper
+    /// script that runs it over `ssh` on a login node.
+    pub squeue_command: String,
+
+    /// Extra arguments passed to `squeue_command`.
+    pub squeue_args: Vec<String>,
+
+    /// How long a `squeue` snapshot is reused before being refreshed.
+    pub snapshot_ttl_secs: u64,
+
+    /// Maximum number of rows put in the HTML results table. The JSON,
+    /// streaming and export endpoints do not apply this cap.
+    pub max_rows: usize,
+
+    /// Authentication settings.
+    pub auth: AuthFileConfig,
+}
+
+/// The `[auth]` section of the config file. Any field may instead be
+/// supplied via the `SLURM_QUERY_AUTH_SECRET`/`SLURM_QUERY_AUTH_PASSWORD`/
+/// `SLURM_QUERY_ALLOWED_USERS` environment variables, which are used only
+/// when the field is unset here.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AuthFileConfig {
+    pub secret: Option<String>,
+    /// Shared password required to log in, alongside a username from
+    /// `allowed_users` (any username when `allowed_users` is unset).
+    pub password: Option<String>,
+    pub allowed_users: Option<Vec<String>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_addr: "127.0.0.1:3000".to_string(),
+            squeue_command: "squeue".to_string(),
+            squeue_args: vec!["--json".to_string()],
+            snapshot_ttl_secs: 10,
+            max_rows: 100_000,
+            auth: AuthFileConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("reading config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("parsing config file {}", path.display()))
+    }
+}
@@ -1,31 +1,57 @@
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::sync::Arc;
 
 use prqlc::{compile, sql::Dialect, Options, Target};
 
 use anyhow::{anyhow, bail, Context, Result};
 
+use async_stream::stream;
 use axum::{
-    extract::Query,
-    http::StatusCode,
-    response::{Html, IntoResponse, Response},
+    extract::{Query, State},
+    http::{header, StatusCode},
+    middleware,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Json, Response,
+    },
     routing::get,
     Router,
 };
+use futures::stream::Stream;
 
 use serde::Deserialize;
 
 use arrow::array::Array;
 use duckdb::Connection;
+use parquet::arrow::ArrowWriter;
 use tempfile::TempDir;
-use tokio::{fs, io, process::Command};
+use tokio::{fs, io, process::Command, sync::mpsc};
 
 mod ansi;
 mod arrow_utils;
+mod auth;
+mod cache;
+mod config;
 mod escape;
+mod pool;
 
 use ansi::strip_ansi;
-use arrow_utils::value_string;
+use arrow_utils::{value_json, value_string};
+use auth::AuthConfig;
+use cache::SnapshotCache;
+use config::Config;
 use escape::{escape_html, escape_query};
+use pool::Pool;
+
+#[derive(Clone)]
+pub(crate) struct AppState {
+    snapshot_cache: Arc<SnapshotCache>,
+    config: Arc<Config>,
+    pool: Pool,
+    pub(crate) auth: Arc<AuthConfig>,
+}
 
 const SCHEMA_HELP: &'static str = r#"
 <details style="margin-left: 8%; margin-top: 10px">
@@ -139,13 +165,14 @@ sort (-num_jobs)
 "#),
 ];
 
-/// Run `squeue --json` and write it to a file called 'squeue.json' in a
-/// temporary directory which is returend. We have to use a temporary directory
-/// so it is possible to close the file without deleting it. That's necessary
-/// on Windows otherwise DuckDB won't read it due to file locking.
-async fn squeue_json() -> Result<TempDir> {
-    let mut child = Command::new("squeue")
-        .arg("--json")
+/// Run the configured scheduler command and write its output to a file
+/// called 'squeue.json' in a temporary directory which is returned. We have
+/// to use a temporary directory so it is possible to close the file without
+/// deleting it. That's necessary on Windows otherwise DuckDB won't read it
+/// due to file locking.
+async fn squeue_json(config: &Config) -> Result<TempDir> {
+    let mut child = Command::new(&config.squeue_command)
+        .args(&config.squeue_args)
         .stdout(Stdio::piped())
         .spawn()?;
 
@@ -169,8 +196,8 @@ async fn squeue_json() -> Result<TempDir> {
     Ok(dir)
 }
 
-async fn query(prql: &str) -> Result<String> {
-    // Compile PRQL to SQL
+/// Compile a PRQL query to the DuckDB SQL dialect.
+fn compile_sql(prql: &str) -> Result<String> {
     let opts = &Options {
         format: true,
         target: Target::Sql(Some(Dialect::DuckDb)),
@@ -178,70 +205,485 @@ async fn query(prql: &str) -> Result<String> {
         // This does nothing, it actually always returns ANSI colours.
         color: false,
     };
-    let sql = compile(&prql, opts).map_err(|e| anyhow!("{}", strip_ansi(&e.to_string())))?;
-
-    dbg!(&sql);
-
-    let json_dir = squeue_json().await?;
-    let json_path = json_dir.path().join("squeue.json");
-
-    // Import JSON into DuckDB
-    let conn = Connection::open_in_memory()?;
+    compile(prql, opts).map_err(|e| anyhow!("{}", strip_ansi(&e.to_string())))
+}
 
+/// Load the `queue` table into `conn` from `json_path`.
+fn load_queue_table(conn: &Connection, json_path: &Path) -> Result<()> {
     conn.execute(
         "CREATE TABLE queue AS SELECT * FROM read_json_auto(?)",
         [json_path.to_string_lossy()],
     )
     .with_context(|| anyhow!("Reading JSON"))?;
 
-    // Security, hopefully.
+    Ok(())
+}
+
+/// Restrict `conn` to the loaded `queue` table: no further local/HTTP file
+/// access and no further configuration changes. This must be the last thing
+/// done to a connection, since it also blocks `COPY ... TO` on local paths.
+fn apply_security_pragmas(conn: &Connection) -> Result<()> {
     conn.execute(
         "SET disabled_filesystems='LocalFileSystem,HTTPFileSystem'",
         [],
     )?;
     conn.execute("SET lock_configuration=true", [])?;
 
-    // Run Query
-    let mut stmt = conn.prepare(&sql)?;
+    Ok(())
+}
+
+/// Open an in-memory DuckDB connection with the `queue` table loaded from
+/// `json_path` and the security pragmas applied. Used for pooled query
+/// connections, which never need local file access again after loading.
+pub(crate) fn open_queue_connection(json_path: &Path) -> Result<Connection> {
+    let conn = Connection::open_in_memory()?;
+    load_queue_table(&conn, json_path)?;
+    apply_security_pragmas(&conn)?;
+    Ok(conn)
+}
+
+async fn query(prql: &str, pool: &Pool, max_rows: usize) -> Result<String> {
+    let sql = compile_sql(prql)?;
+
+    pool::with_connection(pool, move |conn| {
+        // Run Query
+        let mut stmt = conn.prepare(&sql)?;
+
+        stmt.execute([])?;
+
+        let mut table_html = "<table id=\"results\">".to_string();
+
+        let mut header_printed = false;
+        let mut rows_written = 0;
+        let mut truncated = false;
+        'batches: while let Some(batch) = stmt.step() {
+            if !header_printed {
+                header_printed = true;
+                table_html += "<thead><tr>";
+                for col in batch.column_names() {
+                    table_html += "<th>";
+                    table_html += &escape_html(&col);
+                    table_html += "</th>";
+                }
+                table_html += "</tr></thead><tbody>";
+            }
+
+            for row in 0..batch.len() {
+                if rows_written >= max_rows {
+                    truncated = true;
+                    break 'batches;
+                }
+
+                table_html += "<tr>";
+                for col in batch.columns() {
+                    table_html += "<td>";
+                    table_html += &escape_html(&value_string(col, row));
+                    table_html += "</td>";
+                }
+                table_html += "</tr>";
+                rows_written += 1;
+            }
+        }
+
+        table_html += "</tbody></table>";
+
+        if truncated {
+            table_html += &format!("<p>Results truncated at {max_rows} rows.</p>");
+        }
+
+        Ok(table_html)
+    })
+    .await
+}
+
+/// Like `query()` but returns the result set as JSON (`{columns, rows}`)
+/// instead of an HTML table, for scripting and dashboard use.
+async fn query_json(prql: &str, pool: &Pool) -> Result<serde_json::Value> {
+    let sql = compile_sql(prql)?;
+
+    pool::with_connection(pool, move |conn| {
+        let mut stmt = conn.prepare(&sql)?;
+
+        stmt.execute([])?;
+
+        let mut columns: Vec<String> = Vec::new();
+        let mut rows: Vec<serde_json::Value> = Vec::new();
+
+        while let Some(batch) = stmt.step() {
+            if columns.is_empty() {
+                columns = batch.column_names().into_iter().map(String::from).collect();
+            }
+
+            for row in 0..batch.len() {
+                let mut obj = serde_json::Map::with_capacity(columns.len());
+                for (col_name, col) in columns.iter().zip(batch.columns()) {
+                    obj.insert(col_name.clone(), value_json(col, row));
+                }
+                rows.push(serde_json::Value::Object(obj));
+            }
+        }
+
+        Ok(serde_json::json!({ "columns": columns, "rows": rows }))
+    })
+    .await
+}
+
+/// Downloadable result formats, in addition to the default `html` table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExportFormat {
+    Html,
+    Csv,
+    Ndjson,
+    Parquet,
+}
+
+impl ExportFormat {
+    fn from_param(s: Option<&str>) -> Result<Self> {
+        match s.unwrap_or("html") {
+            "html" => Ok(Self::Html),
+            "csv" => Ok(Self::Csv),
+            "ndjson" => Ok(Self::Ndjson),
+            "parquet" => Ok(Self::Parquet),
+            other => bail!("unknown format `{other}`, expected html, csv, ndjson or parquet"),
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Html => "text/html; charset=utf-8",
+            Self::Csv => "text/csv",
+            Self::Ndjson => "application/x-ndjson",
+            Self::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Html => "html",
+            Self::Csv => "csv",
+            Self::Ndjson => "ndjson",
+            Self::Parquet => "parquet",
+        }
+    }
+}
+
+/// Escape a CSV field per RFC 4180: quote it if it contains a comma, quote
+/// or newline, doubling any embedded quotes.
+fn csv_field(s: &str) -> String {
+    if s.contains(['"', ',', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Render the rows of the already-executed `stmt` as a CSV document.
+fn render_csv(stmt: &mut duckdb::Statement) -> Result<Vec<u8>> {
+    let mut out = String::new();
+    let mut header_written = false;
+
+    while let Some(batch) = stmt.step() {
+        if !header_written {
+            header_written = true;
+            out.push_str(
+                &batch
+                    .column_names()
+                    .into_iter()
+                    .map(|c| csv_field(&c))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            );
+            out.push_str("\r\n");
+        }
+
+        for row in 0..batch.len() {
+            out.push_str(
+                &batch
+                    .columns()
+                    .iter()
+                    .map(|col| csv_field(&value_string(col, row)))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            );
+            out.push_str("\r\n");
+        }
+    }
+
+    Ok(out.into_bytes())
+}
+
+/// Render the rows of the already-executed `stmt` as newline-delimited JSON,
+/// one object per row.
+fn render_ndjson(stmt: &mut duckdb::Statement) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut columns: Vec<String> = Vec::new();
+
+    while let Some(batch) = stmt.step() {
+        if columns.is_empty() {
+            columns = batch.column_names().into_iter().map(String::from).collect();
+        }
+
+        for row in 0..batch.len() {
+            let mut obj = serde_json::Map::with_capacity(columns.len());
+            for (col_name, col) in columns.iter().zip(batch.columns()) {
+                obj.insert(col_name.clone(), value_json(col, row));
+            }
+            serde_json::to_writer(&mut out, &serde_json::Value::Object(obj))
+                .expect("serde_json::Value always serializes");
+            out.push(b'\n');
+        }
+    }
+
+    Ok(out)
+}
+
+/// Render the rows of the already-executed `stmt` as a Parquet file, by handing the
+/// already-fetched `RecordBatch`es (which carry their own schema) straight
+/// to an Arrow Parquet writer.
+fn render_parquet(stmt: &mut duckdb::Statement) -> Result<Vec<u8>> {
+    let mut writer: Option<ArrowWriter<Vec<u8>>> = None;
+
+    while let Some(batch) = stmt.step() {
+        if writer.is_none() {
+            writer = Some(ArrowWriter::try_new(Vec::new(), batch.schema(), None)?);
+        }
+        writer.as_mut().expect("just initialized above").write(&batch)?;
+    }
+
+    match writer {
+        Some(w) => Ok(w.into_inner()?),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Run `prql` and export the result as CSV/NDJSON/Parquet, returning the raw
+/// file bytes so they can be streamed back as an attachment. `export_format`
+/// must not be `ExportFormat::Html`.
+///
+/// This runs `prql` through the same pragma-locked pool connection as
+/// `query()`/`query_json()`, then builds the output file from the already-
+/// fetched rows in Rust rather than handing the query engine itself
+/// filesystem access: `prql` compiles to raw SQL (via PRQL s-strings), so a
+/// connection capable of `COPY ... TO` a local path would equally be able to
+/// `read_json_auto('/etc/passwd')` or pull in an `httpfs` URL.
+async fn query_export(prql: &str, pool: &Pool, export_format: ExportFormat) -> Result<Vec<u8>> {
+    let sql = compile_sql(prql)?;
+
+    pool::with_connection(pool, move |conn| {
+        let mut stmt = conn.prepare(&sql)?;
+        stmt.execute([])?;
+
+        match export_format {
+            ExportFormat::Csv => render_csv(&mut stmt),
+            ExportFormat::Ndjson => render_ndjson(&mut stmt),
+            ExportFormat::Parquet => render_parquet(&mut stmt),
+            ExportFormat::Html => unreachable!("HTML results aren't exported via query_export"),
+        }
+    })
+    .await
+}
+
+/// Run the blocking DuckDB side of `query_stream` to completion, sending one
+/// `schema` event and then one `batch` event per `RecordBatch` down `tx` as
+/// they're stepped. Meant to run on a blocking thread via `spawn_blocking`:
+/// `conn`/`stmt` never have to be `Send` across an `.await` point because
+/// they never cross one — the whole loop is synchronous.
+fn run_query_stream(json_path: PathBuf, sql: String, tx: &mpsc::Sender<Event>) {
+    let send = |event: Event| tx.blocking_send(event).is_ok();
+
+    let conn = match open_queue_connection(&json_path) {
+        Ok(conn) => conn,
+        Err(e) => {
+            send(Event::default().event("error").data(e.to_string()));
+            return;
+        }
+    };
+
+    let mut stmt = match conn.prepare(&sql) {
+        Ok(stmt) => stmt,
+        Err(e) => {
+            send(Event::default().event("error").data(e.to_string()));
+            return;
+        }
+    };
 
-    stmt.execute([])?;
+    if let Err(e) = stmt.execute([]) {
+        send(Event::default().event("error").data(e.to_string()));
+        return;
+    }
 
-    let mut table_html = "<table id=\"results\">".to_string();
+    let mut columns: Vec<String> = Vec::new();
 
-    let mut header_printed = false;
     while let Some(batch) = stmt.step() {
-        if !header_printed {
-            header_printed = true;
-            table_html += "<thead><tr>";
-            for col in batch.column_names() {
-                table_html += "<th>";
-                table_html += &escape_html(&col);
-                table_html += "</th>";
+        if columns.is_empty() {
+            columns = batch.column_names().into_iter().map(String::from).collect();
+            let schema = serde_json::json!({ "columns": columns });
+            if !send(
+                Event::default()
+                    .event("schema")
+                    .json_data(schema)
+                    .expect("schema is always valid JSON"),
+            ) {
+                return;
             }
-            table_html += "</tr></thead><tbody>";
         }
 
+        let mut rows = Vec::with_capacity(batch.len());
         for row in 0..batch.len() {
-            table_html += "<tr>";
-            for col in batch.columns() {
-                table_html += "<td>";
-                table_html += &escape_html(&value_string(col, row));
-                table_html += "</td>";
+            let mut obj = serde_json::Map::with_capacity(columns.len());
+            for (col_name, col) in columns.iter().zip(batch.columns()) {
+                obj.insert(col_name.clone(), value_json(col, row));
             }
-            table_html += "</tr>";
+            rows.push(serde_json::Value::Object(obj));
+        }
+
+        let batch_json = serde_json::json!({ "rows": rows });
+        if !send(
+            Event::default()
+                .event("batch")
+                .json_data(batch_json)
+                .expect("batch is always valid JSON"),
+        ) {
+            return;
         }
     }
+}
+
+/// Like `query_json()` but emits one SSE event per `RecordBatch` as it's
+/// stepped, instead of buffering the whole result set first. Unless an
+/// `error` event comes first, the first event is a `schema` event with the
+/// column names, so the client can build the table header before any rows
+/// arrive.
+///
+/// The DuckDB work runs on a blocking thread via `spawn_blocking`, feeding
+/// events back over an mpsc channel — every other DB path here offloads its
+/// blocking work the same way (`pool::with_connection` uses `interact`,
+/// `query_export` uses the pool), so this one shouldn't tie up a Tokio
+/// worker thread for the whole result set either.
+fn query_stream(
+    prql: String,
+    snapshot_cache: Arc<SnapshotCache>,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    stream! {
+        let sql = match compile_sql(&prql) {
+            Ok(sql) => sql,
+            Err(e) => {
+                yield Ok(Event::default().event("error").data(e.to_string()));
+                return;
+            }
+        };
+
+        let json_dir = match snapshot_cache.get().await {
+            Ok(dir) => dir,
+            Err(e) => {
+                yield Ok(Event::default().event("error").data(e.to_string()));
+                return;
+            }
+        };
+        let json_path = json_dir.path().join("squeue.json");
+
+        let (tx, mut rx) = mpsc::channel::<Event>(8);
+        let task = tokio::task::spawn_blocking(move || run_query_stream(json_path, sql, &tx));
 
-    table_html += "</tbody></table>";
+        while let Some(event) = rx.recv().await {
+            yield Ok(event);
+        }
+
+        // Propagate a panic in the blocking task rather than silently
+        // ending the stream; errors reaching the client already went out
+        // as `error` events above.
+        if let Err(e) = task.await {
+            yield Ok(Event::default().event("error").data(format!("stream task panicked: {e}")));
+        }
+    }
+}
 
-    Ok(table_html)
+/// `GET /api/stream?prql=...` — same query as `/api/query` but streamed
+/// incrementally over Server-Sent Events instead of buffered into one
+/// response.
+async fn api_query_stream(
+    State(state): State<AppState>,
+    Query(params): Query<Params>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let prql = params.prql.unwrap_or_default();
+    Sse::new(query_stream(prql, state.snapshot_cache)).keep_alive(KeepAlive::default())
 }
 
+/// Path to the config file, overridable via the first command-line argument.
+const DEFAULT_CONFIG_PATH: &str = "slurm_query.toml";
+
 #[tokio::main]
 async fn main() {
-    let app = Router::new().route("/", get(index));
+    let config_path =
+        std::env::args().nth(1).unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string());
+    let config = Arc::new(match Config::load(Path::new(&config_path)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("warning: {e:#}, using default config");
+            Config::default()
+        }
+    });
+
+    let snapshot_cache = Arc::new(SnapshotCache::new(Arc::clone(&config)));
+    let pool = Pool::builder(pool::Manager::new(Arc::clone(&snapshot_cache)))
+        .build()
+        .expect("building DuckDB connection pool");
+    let auth = Arc::new(AuthConfig {
+        secret: config
+            .auth
+            .secret
+            .clone()
+            .or_else(|| std::env::var("SLURM_QUERY_AUTH_SECRET").ok())
+            .expect(
+                "auth secret must be set via the config file's `[auth] secret` \
+                 or the SLURM_QUERY_AUTH_SECRET environment variable",
+            ),
+        password: config
+            .auth
+            .password
+            .clone()
+            .or_else(|| std::env::var("SLURM_QUERY_AUTH_PASSWORD").ok())
+            .expect(
+                "auth password must be set via the config file's `[auth] password` \
+                 or the SLURM_QUERY_AUTH_PASSWORD environment variable",
+            ),
+        allowed_users: config.auth.allowed_users.clone().or_else(|| {
+            std::env::var("SLURM_QUERY_ALLOWED_USERS")
+                .ok()
+                .map(|users| users.split(',').map(|u| u.trim().to_string()).collect())
+        }),
+    });
+
+    let state = AppState {
+        snapshot_cache,
+        config: Arc::clone(&config),
+        pool,
+        auth,
+    };
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
+    // The human-facing page redirects to `/login` instead of returning a
+    // bare 401, so a browser without a session cookie can actually log in;
+    // the JSON/SSE API routes just return 401, since a redirect wouldn't
+    // make sense for a script or fetch() caller.
+    let page = Router::new().route("/", get(index)).route_layer(
+        middleware::from_fn_with_state(state.clone(), auth::require_auth_redirect),
+    );
+
+    let api = Router::new()
+        .route("/api/query", get(api_query_get).post(api_query_post))
+        .route("/api/stream", get(api_query_stream))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_auth,
+        ));
+
+    let app = Router::new()
+        .route("/login", get(auth::login_form).post(auth::login))
+        .merge(page)
+        .merge(api)
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(&config.listen_addr)
         .await
         .unwrap();
     println!("listening on http://{}", listener.local_addr().unwrap());
@@ -252,16 +694,62 @@ async fn main() {
 #[allow(dead_code)]
 struct Params {
     prql: Option<String>,
+    /// `html` (default), `csv`, `ndjson` or `parquet`.
+    format: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiQueryBody {
+    prql: String,
+}
+
+/// `GET /api/query?prql=...` — run a query and return it as JSON.
+async fn api_query_get(
+    State(state): State<AppState>,
+    Query(params): Query<Params>,
+) -> std::result::Result<Json<serde_json::Value>, AppError> {
+    let prql = params.prql.ok_or_else(|| anyhow!("missing `prql` parameter"))?;
+    Ok(Json(query_json(&prql, &state.pool).await?))
 }
 
-async fn index(Query(params): Query<Params>) -> std::result::Result<Html<String>, AppError> {
+/// `POST /api/query` with a `{"prql": "..."}` body — same as the GET form.
+async fn api_query_post(
+    State(state): State<AppState>,
+    Json(body): Json<ApiQueryBody>,
+) -> std::result::Result<Json<serde_json::Value>, AppError> {
+    Ok(Json(query_json(&body.prql, &state.pool).await?))
+}
+
+async fn index(
+    State(state): State<AppState>,
+    Query(params): Query<Params>,
+) -> std::result::Result<Response, AppError> {
+    let format = ExportFormat::from_param(params.format.as_deref())?;
+
+    // Non-HTML formats are downloads, not part of the page, so short-circuit
+    // before building any HTML.
+    if let (Some(q), false) = (&params.prql, format == ExportFormat::Html) {
+        let bytes = query_export(q, &state.pool, format).await?;
+        return Ok((
+            [
+                (header::CONTENT_TYPE, format.content_type().to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"result.{}\"", format.extension()),
+                ),
+            ],
+            bytes,
+        )
+            .into_response());
+    }
+
     let escaped_prql = match &params.prql {
         Some(q) => escape_html(q),
         None => "".to_string(),
     };
 
     let result_html = match &params.prql {
-        Some(q) => query(q).await?,
+        Some(q) => query(q, &state.pool, state.config.max_rows).await?,
         None => {
             let mut examples = "<ul style=\"margin-left: 5%\">".to_string();
             for (name, example) in EXAMPLES {
@@ -320,11 +808,12 @@ const dataTable = new simpleDatatables.DataTable('#results', {{  perPageSelect:
 </body>
 </html>
 "#
-    )))
+    ))
+    .into_response())
 }
 
 // Make our own error that wraps `anyhow::Error`.
-struct AppError(anyhow::Error);
+pub(crate) struct AppError(anyhow::Error);
 
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
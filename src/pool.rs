@@ -0,0 +1,95 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use deadpool::managed;
+use deadpool_sync::SyncWrapper;
+use duckdb::Connection;
+
+use crate::{cache::SnapshotCache, open_queue_connection};
+
+pub type Pool = managed::Pool<Manager>;
+
+/// A pooled DuckDB connection plus the snapshot path its `queue` table was
+/// last loaded from, so `Manager::recycle` can tell whether it needs
+/// reloading.
+struct PooledConn {
+    conn: Connection,
+    loaded_from: PathBuf,
+}
+
+/// Builds/recycles pooled DuckDB connections that already have the `queue`
+/// table loaded from the current snapshot, so a request that checks one out
+/// doesn't have to re-run `read_json_auto` and re-apply the security
+/// pragmas every time.
+pub struct Manager {
+    snapshot_cache: Arc<SnapshotCache>,
+}
+
+impl Manager {
+    pub fn new(snapshot_cache: Arc<SnapshotCache>) -> Self {
+        Self { snapshot_cache }
+    }
+}
+
+#[async_trait]
+impl managed::Manager for Manager {
+    type Type = SyncWrapper<PooledConn>;
+    type Error = anyhow::Error;
+
+    async fn create(&self) -> Result<Self::Type> {
+        let json_path = self.snapshot_cache.get().await?.path().join("squeue.json");
+        SyncWrapper::new(move || {
+            let conn = open_queue_connection(&json_path)?;
+            Ok(PooledConn {
+                conn,
+                loaded_from: json_path,
+            })
+        })
+        .await
+        .context("creating pooled DuckDB connection")?
+    }
+
+    async fn recycle(
+        &self,
+        pooled: &mut Self::Type,
+        _metrics: &managed::Metrics,
+    ) -> managed::RecycleResult<Self::Error> {
+        let json_path = self.snapshot_cache.get().await?.path().join("squeue.json");
+
+        // A pooled connection has `disabled_filesystems`/`lock_configuration`
+        // set, so it can't re-run `read_json_auto` in place once the
+        // snapshot refreshes. Instead of trying to reload through that,
+        // reject the stale connection here: deadpool drops it and calls
+        // `create()` again for a fresh one loaded from the new snapshot.
+        let stale = pooled
+            .interact(move |pooled| pooled.loaded_from != json_path)
+            .await
+            .map_err(|e| managed::RecycleError::Message(e.to_string().into()))?;
+
+        if stale {
+            return Err(managed::RecycleError::Message(
+                "snapshot refreshed since this connection was created".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Check out a pooled connection and run `f` with it on the pool's blocking
+/// thread, returning its result.
+pub async fn with_connection<F, R>(pool: &Pool, f: F) -> Result<R>
+where
+    F: FnOnce(&Connection) -> Result<R> + Send + 'static,
+    R: Send + 'static,
+{
+    let conn = pool
+        .get()
+        .await
+        .context("checking out pooled DuckDB connection")?;
+    conn.interact(move |pooled| f(&pooled.conn))
+        .await
+        .map_err(|e| anyhow!("pooled connection task panicked: {e}"))?
+}